@@ -0,0 +1,283 @@
+//! Typed deserialization of an `EnvFile`'s store via `serde`.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::de::{self, DeserializeOwned, DeserializeSeed, Error as _, MapAccess, Visitor};
+
+/// An error encountered while deserializing an `EnvFile`'s store into a
+/// typed struct.
+#[derive(Debug)]
+pub enum DeError {
+    /// A value could not be coerced into the type the target field expects.
+    InvalidValue { key: String, value: String, expected: &'static str },
+    /// An error raised by `serde` itself, e.g. from a missing required field.
+    Custom(String),
+}
+
+impl fmt::Display for DeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeError::InvalidValue { key, value, expected } => write!(
+                f, "key `{}` has value `{}`, which is not a valid {}", key, value, expected
+            ),
+            DeError::Custom(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for DeError {}
+
+impl de::Error for DeError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        DeError::Custom(msg.to_string())
+    }
+}
+
+/// Deserializes an `EnvFile`'s store into a user-defined struct.
+///
+/// Struct fields are matched against store keys case-insensitively, which
+/// plays nicely with the `SCREAMING_SNAKE_CASE` convention of environment
+/// variables. Keys with no matching field are ignored.
+pub struct EnvDeserializer<'a> {
+    store: &'a BTreeMap<String, String>,
+}
+
+impl<'a> EnvDeserializer<'a> {
+    /// Deserialize `store` into `T`.
+    pub fn deserialize<T: DeserializeOwned>(store: &'a BTreeMap<String, String>) -> Result<T, DeError> {
+        T::deserialize(EnvDeserializer { store })
+    }
+}
+
+impl<'de, 'a> de::Deserializer<'de> for EnvDeserializer<'a> {
+    type Error = DeError;
+
+    fn deserialize_struct<V>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_map(FieldMap { store: self.store, fields, index: 0 })
+    }
+
+    fn deserialize_any<V>(self, _visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        Err(DeError::custom("an EnvFile can only be deserialized into a struct"))
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes
+        byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map enum identifier ignored_any
+    }
+}
+
+/// Walks a struct's field list, yielding only the fields present (under
+/// case-insensitive comparison) in the store.
+struct FieldMap<'a> {
+    store: &'a BTreeMap<String, String>,
+    fields: &'static [&'static str],
+    index: usize,
+}
+
+impl<'de, 'a> MapAccess<'de> for FieldMap<'a> {
+    type Error = DeError;
+
+    fn next_key_seed<K>(&mut self, seed: K) -> Result<Option<K::Value>, Self::Error>
+    where
+        K: DeserializeSeed<'de>,
+    {
+        while self.index < self.fields.len() {
+            let field = self.fields[self.index];
+            self.index += 1;
+            if find_key(self.store, field).is_some() {
+                return seed.deserialize(de::value::StrDeserializer::new(field)).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    fn next_value_seed<V>(&mut self, seed: V) -> Result<V::Value, Self::Error>
+    where
+        V: DeserializeSeed<'de>,
+    {
+        let field = self.fields[self.index - 1];
+        let (key, value) = find_key(self.store, field).expect("presence checked by next_key_seed");
+        seed.deserialize(ValueDeserializer { key, value })
+    }
+}
+
+/// Deserializes a single store value, coercing it on demand based on the
+/// type the target field expects.
+struct ValueDeserializer<'a> {
+    key:   &'a str,
+    value: &'a str,
+}
+
+impl<'a> ValueDeserializer<'a> {
+    fn invalid(&self, expected: &'static str) -> DeError {
+        DeError::InvalidValue { key: self.key.to_owned(), value: self.value.to_owned(), expected }
+    }
+
+    fn parse<T: std::str::FromStr>(&self, expected: &'static str) -> Result<T, DeError> {
+        self.value.parse().map_err(|_| self.invalid(expected))
+    }
+}
+
+macro_rules! deserialize_parsed {
+    ($method:ident, $visit:ident, $ty:ty, $expected:expr) => {
+        fn $method<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+        where
+            V: Visitor<'de>,
+        {
+            visitor.$visit(self.parse::<$ty>($expected)?)
+        }
+    };
+}
+
+impl<'de, 'a> de::Deserializer<'de> for ValueDeserializer<'a> {
+    type Error = DeError;
+
+    fn deserialize_any<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bool<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        match self.value {
+            "true" | "1" => visitor.visit_bool(true),
+            "false" | "0" => visitor.visit_bool(false),
+            _ => Err(self.invalid("boolean")),
+        }
+    }
+
+    deserialize_parsed!(deserialize_i8, visit_i8, i8, "i8");
+    deserialize_parsed!(deserialize_i16, visit_i16, i16, "i16");
+    deserialize_parsed!(deserialize_i32, visit_i32, i32, "i32");
+    deserialize_parsed!(deserialize_i64, visit_i64, i64, "i64");
+    deserialize_parsed!(deserialize_u8, visit_u8, u8, "u8");
+    deserialize_parsed!(deserialize_u16, visit_u16, u16, "u16");
+    deserialize_parsed!(deserialize_u32, visit_u32, u32, "u32");
+    deserialize_parsed!(deserialize_u64, visit_u64, u64, "u64");
+    deserialize_parsed!(deserialize_f32, visit_f32, f32, "f32");
+    deserialize_parsed!(deserialize_f64, visit_f64, f64, "f64");
+
+    fn deserialize_option<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_str<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_str(self.value)
+    }
+
+    fn deserialize_string<V>(self, visitor: V) -> Result<V::Value, Self::Error>
+    where
+        V: Visitor<'de>,
+    {
+        visitor.visit_string(self.value.to_owned())
+    }
+
+    serde::forward_to_deserialize_any! {
+        char bytes byte_buf unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+fn find_key<'a>(store: &'a BTreeMap<String, String>, field: &str) -> Option<(&'a str, &'a str)> {
+    store.iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(field))
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Config {
+        host: String,
+        port: u16,
+        debug: bool,
+        ratio: f64,
+        nickname: Option<String>,
+    }
+
+    fn store(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs.iter().map(|&(k, v)| (k.to_owned(), v.to_owned())).collect()
+    }
+
+    #[test]
+    fn deserialize_coerces_and_matches_case_insensitively() {
+        let store = store(&[
+            ("HOST", "localhost"),
+            ("PORT", "8080"),
+            ("DEBUG", "1"),
+            ("RATIO", "0.5"),
+            ("UNKNOWN_KEY", "ignored"),
+        ]);
+
+        let config: Config = EnvDeserializer::deserialize(&store).unwrap();
+        assert_eq!(config, Config {
+            host: "localhost".into(),
+            port: 8080,
+            debug: true,
+            ratio: 0.5,
+            nickname: None,
+        });
+    }
+
+    #[test]
+    fn deserialize_fills_option_when_present() {
+        let store = store(&[
+            ("host", "localhost"),
+            ("port", "80"),
+            ("debug", "false"),
+            ("ratio", "1"),
+            ("nickname", "bud"),
+        ]);
+
+        let config: Config = EnvDeserializer::deserialize(&store).unwrap();
+        assert_eq!(config.nickname, Some("bud".into()));
+    }
+
+    #[test]
+    fn deserialize_reports_invalid_value() {
+        let store = store(&[("host", "localhost"), ("port", "not-a-number"), ("debug", "1"), ("ratio", "1")]);
+
+        let err = EnvDeserializer::deserialize::<Config>(&store).unwrap_err();
+        match err {
+            DeError::InvalidValue { key, expected, .. } => {
+                assert_eq!(key, "port");
+                assert_eq!(expected, "u16");
+            }
+            DeError::Custom(message) => panic!("expected InvalidValue, got Custom({})", message),
+        }
+    }
+
+    #[test]
+    fn deserialize_reports_missing_required_field() {
+        let store = store(&[("host", "localhost")]);
+
+        let err = EnvDeserializer::deserialize::<Config>(&store).unwrap_err();
+        assert!(matches!(err, DeError::Custom(_)));
+    }
+}
@@ -0,0 +1,188 @@
+//! Recursive `${VAR}`/`$VAR` interpolation of store values, with cycle
+//! detection and an escape hatch (`$$`) for a literal dollar sign.
+
+use std::fmt;
+
+/// How `expand`/`get_expanded` should handle a reference to a variable that
+/// is defined in neither the store nor the process environment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissingPolicy {
+    /// Leave the `${VAR}`/`$VAR` reference in the output untouched.
+    Literal,
+    /// Fail with `InterpolationError`.
+    Error,
+}
+
+/// An error encountered while expanding variable references in a value.
+///
+/// `cycle` is empty when the error is an undefined reference under
+/// `MissingPolicy::Error`, and holds the chain of keys that led back to
+/// `key` when it is a genuine cycle.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterpolationError {
+    pub key:   String,
+    pub cycle: Vec<String>,
+}
+
+impl fmt::Display for InterpolationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.cycle.is_empty() {
+            write!(f, "undefined variable referenced: `{}`", self.key)
+        } else {
+            write!(f, "cyclic variable reference to `{}` (chain: {})", self.key, self.cycle.join(" -> "))
+        }
+    }
+}
+
+impl std::error::Error for InterpolationError {}
+
+/// Expand all `${VAR}`/`$VAR` references in `value`, looking each one up
+/// through `lookup` and falling back to `std::env::var`. `chain` holds the
+/// keys currently being expanded, so that a key that reappears in its own
+/// resolution path is reported as a cycle rather than recursing forever.
+///
+/// `lookup` is a callback rather than a `&BTreeMap` so that callers can
+/// resolve references on demand (e.g. by scanning a line list) instead of
+/// being forced to materialize a full copy of the store up front.
+pub fn resolve(
+    lookup: &dyn Fn(&str) -> Option<String>,
+    chain: &mut Vec<String>,
+    value: &str,
+    on_missing: MissingPolicy,
+) -> Result<String, InterpolationError> {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(dollar) = rest.find('$') {
+        out.push_str(&rest[..dollar]);
+        rest = &rest[dollar + 1..];
+
+        if let Some(tail) = rest.strip_prefix('$') {
+            out.push('$');
+            rest = tail;
+            continue;
+        }
+
+        if let Some(tail) = rest.strip_prefix('{') {
+            if let Some(end) = tail.find('}') {
+                let name = &tail[..end];
+                out.push_str(&resolve_reference(lookup, chain, name, on_missing, &format!("${{{}}}", name))?);
+                rest = &tail[end + 1..];
+            } else {
+                out.push_str("${");
+                rest = tail;
+            }
+            continue;
+        }
+
+        let end = if rest.starts_with(is_ident_start) {
+            rest.find(|c: char| !is_ident_continue(c)).unwrap_or(rest.len())
+        } else {
+            0
+        };
+
+        if end == 0 {
+            out.push('$');
+        } else {
+            let name = &rest[..end];
+            out.push_str(&resolve_reference(lookup, chain, name, on_missing, &format!("${}", name))?);
+        }
+        rest = &rest[end..];
+    }
+
+    out.push_str(rest);
+    Ok(out)
+}
+
+fn resolve_reference(
+    lookup: &dyn Fn(&str) -> Option<String>,
+    chain: &mut Vec<String>,
+    name: &str,
+    on_missing: MissingPolicy,
+    literal: &str,
+) -> Result<String, InterpolationError> {
+    if chain.iter().any(|key| key == name) {
+        let mut cycle = chain.clone();
+        cycle.push(name.to_owned());
+        return Err(InterpolationError { key: name.to_owned(), cycle });
+    }
+
+    if let Some(value) = lookup(name) {
+        chain.push(name.to_owned());
+        let result = resolve(lookup, chain, &value, on_missing);
+        chain.pop();
+        return result;
+    }
+
+    if let Ok(value) = std::env::var(name) {
+        return Ok(value);
+    }
+
+    match on_missing {
+        MissingPolicy::Literal => Ok(literal.to_owned()),
+        MissingPolicy::Error => Err(InterpolationError { key: name.to_owned(), cycle: Vec::new() }),
+    }
+}
+
+fn is_ident_start(c: char) -> bool { c.is_ascii_alphabetic() || c == '_' }
+fn is_ident_continue(c: char) -> bool { c.is_ascii_alphanumeric() || c == '_' }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(pairs: &'static [(&'static str, &'static str)]) -> impl Fn(&str) -> Option<String> {
+        move |name| pairs.iter().find(|&&(key, _)| key == name).map(|&(_, value)| value.to_owned())
+    }
+
+    #[test]
+    fn resolve_expands_braced_and_bare_references() {
+        let lookup = lookup(&[("HOST", "localhost"), ("PORT", "8080")]);
+
+        let result = resolve(&lookup, &mut vec![], "http://${HOST}:$PORT/", MissingPolicy::Literal).unwrap();
+        assert_eq!(result, "http://localhost:8080/");
+    }
+
+    #[test]
+    fn resolve_leaves_escaped_dollar_literal() {
+        let lookup = lookup(&[]);
+
+        let result = resolve(&lookup, &mut vec![], "price: $$5", MissingPolicy::Literal).unwrap();
+        assert_eq!(result, "price: $5");
+    }
+
+    #[test]
+    fn resolve_leaves_missing_reference_literal_by_default() {
+        let lookup = lookup(&[]);
+
+        let result = resolve(&lookup, &mut vec![], "${UNDEFINED}", MissingPolicy::Literal).unwrap();
+        assert_eq!(result, "${UNDEFINED}");
+    }
+
+    #[test]
+    fn resolve_errors_on_missing_reference_when_policy_is_error() {
+        let lookup = lookup(&[]);
+
+        let err = resolve(&lookup, &mut vec![], "$UNDEFINED", MissingPolicy::Error).unwrap_err();
+        assert_eq!(err.key, "UNDEFINED");
+        assert!(err.cycle.is_empty());
+    }
+
+    #[test]
+    fn resolve_detects_direct_cycle() {
+        let lookup = lookup(&[("A", "$A")]);
+
+        let err = resolve(&lookup, &mut vec!["A".to_owned()], "$A", MissingPolicy::Literal).unwrap_err();
+        assert_eq!(err.key, "A");
+        assert_eq!(err.cycle, vec!["A".to_owned(), "A".to_owned()]);
+    }
+
+    #[test]
+    fn resolve_detects_indirect_cycle() {
+        let lookup = lookup(&[("A", "$B"), ("B", "$A")]);
+
+        let err = resolve(&lookup, &mut vec!["A".to_owned()], "$B", MissingPolicy::Literal).unwrap_err();
+        assert_eq!(err.key, "A");
+        assert_eq!(err.cycle, vec!["A".to_owned(), "B".to_owned(), "A".to_owned()]);
+    }
+}
@@ -1,6 +1,6 @@
 //! Libary for parsing environment files into an in-memory map.
-//! 
-//! ```rust
+//!
+//! ```rust,no_run
 //! extern crate envfile;
 //! 
 //! use envfile::EnvFile;
@@ -10,7 +10,7 @@
 //! fn main() -> io::Result<()> {
 //!     let mut envfile = EnvFile::new(&Path::new("examples/test.env"))?;
 //! 
-//!     for (key, value) in &envfile.store {
+//!     for (key, value) in &envfile.store() {
 //!         println!("{}: {}", key, value);
 //!     }
 //! 
@@ -23,92 +23,314 @@
 //! }
 //! ```
 
-use std::collections::BTreeMap;
-use std::fs::File;
-use std::io::{self, Read, Write};
+extern crate serde;
+
+mod de;
+mod expand;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{self, Write};
 use std::path::Path;
+use std::process::{self, Child, Command};
 use std::str;
 
+pub use de::DeError;
+pub use expand::{InterpolationError, MissingPolicy};
+
+/// A single line of a parsed environment file.
+///
+/// Keeping the file's original line structure, rather than only the parsed
+/// key/value pairs, is what lets `write` preserve comments, blank lines and
+/// ordering instead of re-emitting the store as a freshly sorted dump.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Line {
+    /// A `KEY=value` assignment. `prefix` holds any text before the key
+    /// itself, e.g. leading whitespace or an `export ` keyword, so it can
+    /// be reproduced verbatim.
+    KeyValue { prefix: String, key: String, value: String },
+    /// A `#`-prefixed comment line, kept verbatim.
+    Comment(String),
+    /// An empty line.
+    Blank,
+    /// Any other line that doesn't parse as a key/value pair, kept verbatim.
+    Raw(String),
+}
+
 /// An opened environment file, whose contents are buffered into memory.
 pub struct EnvFile<'a> {
     /// Where the environment file exists in memory.
     pub path:  &'a Path,
-    /// The data that was parsed from the file.
-    pub store: BTreeMap<String, String>,
+    /// The file's lines, in their original order, preserving comments,
+    /// blank lines and unparsed text.
+    pub lines: Vec<Line>,
+    /// Keys that have been explicitly removed, and should be unset even if
+    /// inherited from the parent process when configuring a `Command`.
+    pub removals: BTreeSet<String>,
+    /// How `expand`/`get_expanded` handle a reference to an undefined
+    /// variable. Defaults to `MissingPolicy::Literal`.
+    pub on_missing: MissingPolicy,
+    /// Whether the source file ended in a trailing newline, so `write` can
+    /// reproduce that exactly instead of always appending one. `update`
+    /// sets it to `true` when it appends a new key.
+    trailing_newline: bool,
 }
 
 impl<'a> EnvFile<'a> {
     /// Open and parse an environment file.
     pub fn new(path: &'a Path) -> io::Result<EnvFile<'a>> {
         let data = read(path)?;
-        let mut store = BTreeMap::new();
-
-        let values = data.split(|&x| x == b'\n').flat_map(|entry| {
-            entry.iter().position(|&x| x == b'=').and_then(|pos| {
-                String::from_utf8(entry[..pos].to_owned()).ok()
-                    .and_then(|x| {
-                        String::from_utf8(entry[pos+1..].to_owned()).ok().map(|y| (x, y))
-                    })
-            })
-        });
+        let text = String::from_utf8(data)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
 
-        for (key, value) in values {
-            store.insert(key, value);
+        let mut raw_lines: Vec<&str> = text.split('\n').collect();
+        let trailing_newline = raw_lines.last() == Some(&"");
+        if trailing_newline {
+            raw_lines.pop();
         }
 
-        Ok(EnvFile { path, store })
+        let lines = raw_lines.into_iter().map(parse_line).collect();
+
+        Ok(EnvFile {
+            path,
+            lines,
+            removals: BTreeSet::new(),
+            on_missing: MissingPolicy::Literal,
+            trailing_newline,
+        })
+    }
+
+    /// A map view of the parsed key/value entries, derived from `lines`.
+    /// Later assignments to the same key take precedence, matching `get`.
+    pub fn store(&self) -> BTreeMap<String, String> {
+        let mut store = BTreeMap::new();
+        for line in &self.lines {
+            if let Line::KeyValue { key, value, .. } = line {
+                store.insert(key.clone(), value.clone());
+            }
+        }
+        store
     }
 
     /// Update or insert a key into the map.
+    ///
+    /// Mutates the value in place on its existing line, if any; otherwise
+    /// the new assignment is appended.
     pub fn update(&mut self, key: &str, value: &str) {
-        self.store.insert(key.into(), value.into());
+        self.removals.remove(key);
+
+        for line in self.lines.iter_mut().rev() {
+            if let Line::KeyValue { key: existing, value: existing_value, .. } = line {
+                if existing == key {
+                    *existing_value = value.into();
+                    return;
+                }
+            }
+        }
+
+        self.lines.push(Line::KeyValue { prefix: String::new(), key: key.into(), value: value.into() });
+        self.trailing_newline = true;
+    }
+
+    /// Remove a key from the map, marking it for removal from a configured
+    /// `Command`'s environment even if the parent process has it set.
+    pub fn remove(&mut self, key: &str) {
+        self.removals.insert(key.into());
+        self.lines.retain(|line| match line {
+            Line::KeyValue { key: existing, .. } => existing != key,
+            _ => true,
+        });
     }
 
     /// Fetch a key from the map.
     pub fn get(&self, key: &str) -> Option<&str> {
-        self.store.get(key).as_ref().map(|x| x.as_str())
+        self.lines.iter().rev().find_map(|line| match line {
+            Line::KeyValue { key: existing, value, .. } if existing == key => Some(value.as_str()),
+            _ => None,
+        })
+    }
+
+    /// Layer the parsed store on top of a child process's environment.
+    ///
+    /// This mirrors `Command::env`/`env_remove`: each entry is applied as a
+    /// change on top of whatever the child would otherwise inherit, rather
+    /// than replacing the environment outright, so callers can hand an
+    /// `EnvFile` straight to a `Command` without clobbering the parent's
+    /// environment.
+    pub fn configure(&self, cmd: &mut Command) {
+        for (key, value) in &self.store() {
+            cmd.env(key, value);
+        }
+        for key in &self.removals {
+            cmd.env_remove(key);
+        }
+    }
+
+    /// Spawn `program` with this file's store layered onto its environment.
+    ///
+    /// Equivalent to calling `configure` on a fresh `Command::new(program)`
+    /// and then `spawn`ing it.
+    pub fn spawn_with(&self, program: &str) -> io::Result<Child> {
+        let mut cmd = Command::new(program);
+        self.configure(&mut cmd);
+        cmd.spawn()
+    }
+
+    /// Deserialize the parsed store into a typed struct.
+    ///
+    /// Struct fields are matched against store keys case-insensitively, so
+    /// `SCREAMING_SNAKE_CASE` env keys map onto ordinary struct field names.
+    /// Values are coerced on demand: integers and floats via `FromStr`,
+    /// bools from `true`/`false`/`1`/`0`, and `Option<T>` fields are `None`
+    /// when the key is absent. Keys with no matching field are ignored.
+    pub fn deserialize<T: serde::de::DeserializeOwned>(&self) -> Result<T, DeError> {
+        de::EnvDeserializer::deserialize(&self.store())
+    }
+
+    /// Resolve `${VAR}`/`$VAR` references inside every value against other
+    /// entries in the store and, as a fallback, `std::env::var`, replacing
+    /// each value with its expanded form. A literal dollar sign is written
+    /// as `$$`.
+    ///
+    /// Fails with `InterpolationError` if a key's resolution path loops
+    /// back into itself, or if a reference is undefined and `on_missing` is
+    /// set to `MissingPolicy::Error`.
+    pub fn expand(&mut self) -> Result<(), InterpolationError> {
+        let mut keys = BTreeSet::new();
+        for line in &self.lines {
+            if let Line::KeyValue { key, .. } = line {
+                keys.insert(key.clone());
+            }
+        }
+
+        let lookup = |name: &str| self.get(name).map(str::to_owned);
+        let mut expanded = Vec::with_capacity(keys.len());
+        for key in &keys {
+            let value = self.get(key).expect("key collected from lines").to_owned();
+            let mut chain = vec![key.clone()];
+            expanded.push((key.clone(), expand::resolve(&lookup, &mut chain, &value, self.on_missing)?));
+        }
+        for (key, value) in expanded {
+            self.update(&key, &value);
+        }
+        Ok(())
+    }
+
+    /// Resolve `${VAR}`/`$VAR` references in `key`'s value without mutating
+    /// the store. Returns `None` if `key` is absent, and also if expansion
+    /// fails (a cycle, or an undefined reference under
+    /// `MissingPolicy::Error`); the `InterpolationError` is discarded.
+    ///
+    /// Looks up `key` directly from `lines`, same as `get`, and only
+    /// consults other entries on demand as references are encountered —
+    /// it never materializes the whole store.
+    pub fn get_expanded(&self, key: &str) -> Option<String> {
+        let value = self.get(key)?.to_owned();
+        let lookup = |name: &str| self.get(name).map(str::to_owned);
+        let mut chain = vec![key.to_owned()];
+        expand::resolve(&lookup, &mut chain, &value, self.on_missing).ok()
     }
 
     /// Write the map back to the original file.
     ///
-    /// # Notes
-    /// The keys are written in ascending order.
+    /// Lines are written in their original order: untouched comments, blank
+    /// lines and unparsed text survive verbatim, updated keys are rewritten
+    /// in place, and newly inserted keys are appended. The write is atomic:
+    /// the contents go to a synced temp file in the same directory, which
+    /// is then renamed over the original, keeping its permissions.
     pub fn write(&mut self) -> io::Result<()> {
-        let mut buffer = Vec::with_capacity(1024);
-        for (key, value) in &self.store {
-            buffer.extend_from_slice(key.as_bytes());
-            buffer.push(b'=');
-            buffer.extend_from_slice(value.as_bytes());
-            buffer.push(b'\n');
+        let mut buffer = String::with_capacity(1024);
+        let last = self.lines.len().saturating_sub(1);
+        for (i, line) in self.lines.iter().enumerate() {
+            match line {
+                Line::KeyValue { prefix, key, value } => {
+                    buffer.push_str(prefix);
+                    buffer.push_str(key);
+                    buffer.push('=');
+                    buffer.push_str(value);
+                }
+                Line::Comment(text) | Line::Raw(text) => buffer.push_str(text),
+                Line::Blank => {}
+            }
+            if i < last || self.trailing_newline {
+                buffer.push('\n');
+            }
         }
 
-        write(&self.path, &buffer)
+        write(self.path, buffer.as_bytes())
     }
-}
 
-fn open<P: AsRef<Path>>(path: P) -> io::Result<File> {
-    File::open(&path).map_err(|why| io::Error::new(
-        io::ErrorKind::Other,
-        format!("unable to open file at {:?}: {}", path.as_ref(), why)
-    ))
+    /// Alias for `write`. Writes are atomic by default, so this exists
+    /// purely to make that guarantee explicit at the call site.
+    pub fn write_atomic(&mut self) -> io::Result<()> {
+        self.write()
+    }
 }
 
-fn create<P: AsRef<Path>>(path: P) -> io::Result<File> {
-    File::create(&path).map_err(|why| io::Error::new(
-        io::ErrorKind::Other,
-        format!("unable to create file at {:?}: {}", path.as_ref(), why)
-    ))
+/// Classify a single line of an environment file, capturing enough
+/// structure to reproduce it verbatim on write.
+fn parse_line(raw: &str) -> Line {
+    if raw.is_empty() {
+        return Line::Blank;
+    }
+
+    let after_ws = raw.trim_start();
+    let ws_len = raw.len() - after_ws.len();
+
+    if after_ws.starts_with('#') {
+        return Line::Comment(raw.to_owned());
+    }
+
+    let (prefix_len, rest) = match after_ws
+        .strip_prefix("export")
+        .and_then(|s| s.strip_prefix(char::is_whitespace))
+        .map(|s| s.trim_start())
+    {
+        Some(stripped) => (raw.len() - stripped.len(), stripped),
+        None => (ws_len, after_ws),
+    };
+
+    match rest.find('=') {
+        Some(pos) => Line::KeyValue {
+            prefix: raw[..prefix_len].to_owned(),
+            key:    rest[..pos].to_owned(),
+            value:  rest[pos + 1..].to_owned(),
+        },
+        None => Line::Raw(raw.to_owned()),
+    }
 }
 
 fn read<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
-    open(path).and_then(|mut file| {
-        let mut buffer = Vec::with_capacity(file.metadata().ok().map_or(0, |x| x.len()) as usize);
-        file.read_to_end(&mut buffer).map(|_| buffer)
+    fs::read(&path).map_err(|why| {
+        io::Error::other(format!("unable to read file at {:?}: {}", path.as_ref(), why))
     })
 }
 
+/// Write `contents` to `path` atomically: serialize into a sibling temp
+/// file in the same directory, flush and `sync_all` it, then `rename` it
+/// over the destination, preserving the original file's permissions. This
+/// avoids leaving a half-written file behind if the process is interrupted
+/// or the disk fills up mid-write.
 fn write<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, contents: C) -> io::Result<()> {
-    create(path).and_then(|mut file| file.write_all(contents.as_ref()))
+    let path = path.as_ref();
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let name = path.file_name().map_or_else(|| "envfile".into(), |n| n.to_string_lossy().into_owned());
+    let temp_path = dir.join(format!(".{}.tmp{}", name, process::id()));
+
+    let mut temp_file = File::create(&temp_path).map_err(|why| {
+        io::Error::other(format!("unable to create temp file at {:?}: {}", temp_path, why))
+    })?;
+    temp_file.write_all(contents.as_ref())?;
+    temp_file.sync_all()?;
+
+    if let Ok(metadata) = fs::metadata(path) {
+        temp_file.set_permissions(metadata.permissions())?;
+    }
+    drop(temp_file);
+
+    fs::rename(&temp_path, path).map_err(|why| {
+        io::Error::other(format!("unable to rename {:?} to {:?}: {}", temp_path, path, why))
+    })
 }
 
 #[cfg(test)]
@@ -117,7 +339,6 @@ mod tests {
     use super::*;
     use self::tempdir::TempDir;
     use std::collections::BTreeMap;
-    use std::io::Write;
 
     const SAMPLE: &str = r#"EFI_UUID=DFFD-D047
 HOSTNAME=pop-testing
@@ -135,13 +356,10 @@ ROOT_UUID=2ef950c2-5ce6-4ae0-9fb9-a8c7468fa82c
         let tempdir = TempDir::new("distinst_test").unwrap();
         let path = &tempdir.path().join("recovery.conf");
 
-        {
-            let mut file = create(path).unwrap();
-            file.write_all(SAMPLE.as_bytes()).unwrap();
-        }
+        fs::write(path, SAMPLE.as_bytes()).unwrap();
 
         let env = EnvFile::new(path).unwrap();
-        assert_eq!(&env.store, &{
+        assert_eq!(&env.store(), &{
             let mut map = BTreeMap::new();
             map.insert("HOSTNAME".into(), "pop-testing".into());
             map.insert("LANG".into(), "en_US.UTF-8".into());
@@ -161,10 +379,7 @@ ROOT_UUID=2ef950c2-5ce6-4ae0-9fb9-a8c7468fa82c
         let tempdir = TempDir::new("distinst_test").unwrap();
         let path = &tempdir.path().join("recovery.conf");
 
-        {
-            let mut file = create(path).unwrap();
-            file.write_all(SAMPLE.as_bytes()).unwrap();
-        }
+        fs::write(path, SAMPLE.as_bytes()).unwrap();
 
         let mut env = EnvFile::new(path).unwrap();
         env.write().unwrap();
@@ -172,4 +387,131 @@ ROOT_UUID=2ef950c2-5ce6-4ae0-9fb9-a8c7468fa82c
 
         assert_eq!(copy, SAMPLE.as_bytes());
     }
+
+    #[test]
+    #[cfg(unix)]
+    fn write_preserves_file_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tempdir = TempDir::new("distinst_test").unwrap();
+        let path = &tempdir.path().join("recovery.conf");
+
+        fs::write(path, SAMPLE.as_bytes()).unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(0o640)).unwrap();
+
+        let mut env = EnvFile::new(path).unwrap();
+        env.update("HOSTNAME", "new-host");
+        env.write().unwrap();
+
+        let mode = fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode, 0o640);
+    }
+
+    #[test]
+    fn env_file_preserves_comments_and_blanks() {
+        const WITH_COMMENTS: &str = "# leading comment\n\nexport FOO=bar\n\n# trailing comment\nBAZ=qux\n";
+
+        let tempdir = TempDir::new("distinst_test").unwrap();
+        let path = &tempdir.path().join("recovery.conf");
+
+        fs::write(path, WITH_COMMENTS.as_bytes()).unwrap();
+
+        let mut env = EnvFile::new(path).unwrap();
+        assert_eq!(env.get("FOO"), Some("bar"));
+
+        env.update("FOO", "new-bar");
+        env.update("ADDED", "1");
+        env.write().unwrap();
+
+        let copy = String::from_utf8(read(path).unwrap()).unwrap();
+        assert_eq!(
+            copy,
+            "# leading comment\n\nexport FOO=new-bar\n\n# trailing comment\nBAZ=qux\nADDED=1\n"
+        );
+    }
+
+    #[test]
+    fn configure_applies_store_entries_and_removals() {
+        let tempdir = TempDir::new("distinst_test").unwrap();
+        let path = &tempdir.path().join("recovery.conf");
+
+        fs::write(path, SAMPLE.as_bytes()).unwrap();
+
+        let mut env = EnvFile::new(path).unwrap();
+        env.remove("HOSTNAME");
+
+        let mut cmd = Command::new("true");
+        env.configure(&mut cmd);
+
+        let envs: BTreeMap<_, _> = cmd.get_envs().collect();
+        assert_eq!(envs.get(std::ffi::OsStr::new("LANG")).unwrap(), &Some(std::ffi::OsStr::new("en_US.UTF-8")));
+        assert_eq!(envs.get(std::ffi::OsStr::new("HOSTNAME")).unwrap(), &None);
+    }
+
+    #[test]
+    fn remove_then_update_reinstates_key() {
+        let tempdir = TempDir::new("distinst_test").unwrap();
+        let path = &tempdir.path().join("recovery.conf");
+
+        fs::write(path, SAMPLE.as_bytes()).unwrap();
+
+        let mut env = EnvFile::new(path).unwrap();
+        env.remove("HOSTNAME");
+        assert_eq!(env.get("HOSTNAME"), None);
+        assert!(env.removals.contains("HOSTNAME"));
+
+        env.update("HOSTNAME", "new-host");
+        assert_eq!(env.get("HOSTNAME"), Some("new-host"));
+        assert!(!env.removals.contains("HOSTNAME"));
+
+        let mut cmd = Command::new("true");
+        env.configure(&mut cmd);
+        let envs: BTreeMap<_, _> = cmd.get_envs().collect();
+        assert_eq!(
+            envs.get(std::ffi::OsStr::new("HOSTNAME")).unwrap(),
+            &Some(std::ffi::OsStr::new("new-host"))
+        );
+    }
+
+    #[test]
+    fn spawn_with_launches_program() {
+        let tempdir = TempDir::new("distinst_test").unwrap();
+        let path = &tempdir.path().join("recovery.conf");
+
+        fs::write(path, SAMPLE.as_bytes()).unwrap();
+
+        let env = EnvFile::new(path).unwrap();
+        let status = env.spawn_with("true").unwrap().wait().unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn export_prefix_tolerates_extra_whitespace() {
+        const WITH_EXTRA_WHITESPACE: &str = "export  FOO=bar\nexport\tBAZ=qux\n";
+
+        let tempdir = TempDir::new("distinst_test").unwrap();
+        let path = &tempdir.path().join("recovery.conf");
+
+        fs::write(path, WITH_EXTRA_WHITESPACE.as_bytes()).unwrap();
+
+        let env = EnvFile::new(path).unwrap();
+        assert_eq!(env.get("FOO"), Some("bar"));
+        assert_eq!(env.get("BAZ"), Some("qux"));
+    }
+
+    #[test]
+    fn env_file_preserves_missing_trailing_newline() {
+        const NO_TRAILING_NEWLINE: &str = "FOO=bar\nBAZ=qux";
+
+        let tempdir = TempDir::new("distinst_test").unwrap();
+        let path = &tempdir.path().join("recovery.conf");
+
+        fs::write(path, NO_TRAILING_NEWLINE.as_bytes()).unwrap();
+
+        let mut env = EnvFile::new(path).unwrap();
+        env.write().unwrap();
+
+        let copy = String::from_utf8(read(path).unwrap()).unwrap();
+        assert_eq!(copy, NO_TRAILING_NEWLINE);
+    }
 }